@@ -0,0 +1,88 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Archive-destination support: serialise a copied tree into a single
+//! `.tar` file rather than recreating the directory hierarchy on
+//! disk. Because tar is inherently sequential, all entries are
+//! appended through one [`ArchiveWriter`] by a single consumer thread
+//! draining `work_tx` in walk order (see `operations::archive_worker`).
+
+use std::fs::{File, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use tar::{Builder, EntryType, Header};
+
+use crate::errors::Result;
+
+/// Wraps a `tar::Builder` over a sequential file writer, translating
+/// the same `mode`/`mtime`/`ownership` metadata the disk-tree path
+/// already reads via `symlink_metadata` into tar headers.
+pub struct ArchiveWriter {
+    builder: Builder<File>,
+}
+
+impl ArchiveWriter {
+    pub fn create(dest: &Path) -> Result<Self> {
+        let file = File::create(dest)?;
+        Ok(ArchiveWriter { builder: Builder::new(file) })
+    }
+
+    fn header_for(meta: &Metadata, entry_type: EntryType, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(meta.mode());
+        header.set_mtime(meta.mtime().max(0) as u64);
+        header.set_uid(meta.uid() as u64);
+        header.set_gid(meta.gid() as u64);
+        header
+    }
+
+    /// Append a regular file's content, read from `from`, under
+    /// `path_in_archive`.
+    pub fn append_file(&mut self, path_in_archive: &Path, from: &Path, meta: &Metadata) -> Result<()> {
+        let mut header = Self::header_for(meta, EntryType::Regular, meta.len());
+        let mut infd = File::open(from)?;
+        self.builder.append_data(&mut header, path_in_archive, &mut infd)?;
+        Ok(())
+    }
+
+    /// Append a symlink entry pointing at `link_target`, carrying
+    /// across `mtime`/`ownership` from `meta` like `append_file`/
+    /// `append_dir` do. The mode itself isn't: symlink permission
+    /// bits aren't meaningful on Linux, so the conventional `0o777`
+    /// is kept rather than the source's (irrelevant) lstat mode.
+    pub fn append_symlink(&mut self, path_in_archive: &Path, link_target: &Path, meta: &Metadata) -> Result<()> {
+        let mut header = Self::header_for(meta, EntryType::Symlink, 0);
+        header.set_mode(0o777);
+        self.builder.append_link(&mut header, path_in_archive, link_target)?;
+        Ok(())
+    }
+
+    /// Append a directory entry (no content).
+    pub fn append_dir(&mut self, path_in_archive: &Path, meta: &Metadata) -> Result<()> {
+        let mut header = Self::header_for(meta, EntryType::Directory, 0);
+        self.builder.append_data(&mut header, path_in_archive, std::io::empty())?;
+        Ok(())
+    }
+
+    /// Flush and write the tar end-of-archive marker.
+    pub fn finish(mut self) -> Result<()> {
+        self.builder.finish()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Content-defined chunking and cross-file block deduplication.
+//!
+//! When copying trees with many near-duplicate files (backups, VM
+//! images), re-reading and re-writing bytes that already exist
+//! elsewhere in the destination is wasted I/O. [`Deduplicator`] keeps
+//! a run-wide index of chunk digests to previously-written ranges, so
+//! that [`chunk_boundaries`] lets the driver reflink a matching range
+//! instead of copying it.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Target average chunk size; boundaries are declared whenever the
+/// rolling hash's low bits are all zero against a mask sized for this
+/// average, per the usual content-defined-chunking construction.
+const TARGET_CHUNK_SIZE: usize = 64 * 1024;
+const MIN_CHUNK_SIZE: usize = 16 * 1024;
+const MAX_CHUNK_SIZE: usize = 256 * 1024;
+const ROLLING_WINDOW: usize = 64;
+
+/// Filesystem block size that a reflinked range must be aligned to;
+/// `FICLONERANGE` rejects a misaligned source/dest offset or length
+/// with `EINVAL`. [`chunk_boundaries`] only ever declares a boundary on
+/// a multiple of this (bar the final, end-of-data chunk), so a chunk
+/// it returns is reflink-eligible by construction rather than by luck.
+pub const DEDUP_BLOCK_SIZE: u64 = 4096;
+
+/// A previously-written, already-hashed byte range that a later chunk
+/// with the same digest can be reflinked from instead of copied.
+#[derive(Clone, Debug)]
+pub struct ChunkRef {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Run-wide index of chunk digest -> location, shared across all
+/// copy operations via `Arc<Deduplicator>` so that duplicate blocks
+/// anywhere in the tree can be reflinked rather than re-copied.
+#[derive(Debug, Default)]
+pub struct Deduplicator {
+    index: Mutex<HashMap<blake3::Hash, ChunkRef>>,
+}
+
+impl Deduplicator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the location of a chunk with this digest, if one has
+    /// already been written during this run.
+    pub fn lookup(&self, digest: &blake3::Hash) -> Option<ChunkRef> {
+        let index = self.index.lock().unwrap();
+        index.get(digest).cloned()
+    }
+
+    /// Record a newly-written chunk so later duplicates can reflink it.
+    pub fn insert(&self, digest: blake3::Hash, chunk: ChunkRef) {
+        let mut index = self.index.lock().unwrap();
+        index.entry(digest).or_insert(chunk);
+    }
+}
+
+/// Split `data` into content-defined chunk boundaries using a buzhash
+/// rolling over a fixed-size window. A boundary is only ever tested
+/// for on a [`DEDUP_BLOCK_SIZE`] multiple, where it's declared once
+/// `hash & mask == 0`, with `mask` sized so the average run still
+/// hits [`TARGET_CHUNK_SIZE`] (testing once per block rather than once
+/// per byte divides the target by the block size, so the per-block
+/// hit probability is scaled back up accordingly); [`MIN_CHUNK_SIZE`]/
+/// [`MAX_CHUNK_SIZE`] - themselves block multiples - bound the
+/// variance so a pathological input can't produce degenerate chunks.
+/// Returns the byte length of each chunk, in order; the lengths sum to
+/// `data.len()`.
+///
+/// `eof` marks whether `data` is the whole of a source file (as in a
+/// single in-memory buffer) or a streamed prefix of one with more
+/// still to come: `false` leaves a not-yet-resolved trailing chunk
+/// unreturned (its bytes aren't part of any length in the result)
+/// rather than force a boundary just because the caller's buffer
+/// happened to end there, so a caller streaming in batches can append
+/// the next read to that leftover and call again. Only the final
+/// chunk of a file, once `eof` is true, can land off a block boundary.
+pub fn chunk_boundaries(data: &[u8], eof: bool) -> Vec<usize> {
+    // mask such that, on average, one in every (TARGET_CHUNK_SIZE /
+    // DEDUP_BLOCK_SIZE) block-aligned tests hits: P(hash & mask == 0)
+    // = DEDUP_BLOCK_SIZE/TARGET_CHUNK_SIZE.
+    let blocks_per_chunk = (TARGET_CHUNK_SIZE as u64 / DEDUP_BLOCK_SIZE).next_power_of_two();
+    let mask = blocks_per_chunk - 1;
+
+    let mut lens = Vec::new();
+    let mut start = 0;
+    let mut hash = Buzhash::new();
+
+    if data.is_empty() {
+        return lens;
+    }
+
+    let mut i = 0;
+    while i < data.len() {
+        hash.roll(data, i);
+        let chunk_len = i - start + 1;
+
+        let at_boundary = chunk_len >= MIN_CHUNK_SIZE
+            && chunk_len as u64 % DEDUP_BLOCK_SIZE == 0
+            && (hash.value() & mask) == 0;
+        let forced = chunk_len >= MAX_CHUNK_SIZE;
+        let last = eof && i == data.len() - 1;
+
+        if at_boundary || forced || last {
+            lens.push(chunk_len);
+            start = i + 1;
+            hash = Buzhash::new();
+        }
+
+        i += 1;
+    }
+
+    lens
+}
+
+/// Minimal buzhash: a cyclic-shift rolling hash over the trailing
+/// [`ROLLING_WINDOW`] bytes, using a fixed per-byte-value rotation
+/// table so insertion/removal are both O(1).
+struct Buzhash {
+    value: u64,
+    window: [u8; ROLLING_WINDOW],
+    pos: usize,
+    filled: usize,
+}
+
+impl Buzhash {
+    fn new() -> Self {
+        Buzhash { value: 0, window: [0; ROLLING_WINDOW], pos: 0, filled: 0 }
+    }
+
+    fn value(&self) -> u64 {
+        self.value
+    }
+
+    fn roll(&mut self, data: &[u8], i: usize) {
+        let byte = data[i];
+        let leaving = self.window[self.pos];
+
+        self.value = self.value.rotate_left(1) ^ table(byte);
+        if self.filled == ROLLING_WINDOW {
+            self.value ^= table(leaving).rotate_left(ROLLING_WINDOW as u32 % 64);
+        } else {
+            self.filled += 1;
+        }
+
+        self.window[self.pos] = byte;
+        self.pos = (self.pos + 1) % ROLLING_WINDOW;
+    }
+}
+
+/// Deterministic per-byte-value pseudo-random table, used in place of
+/// a static lookup table so the module has no large const data.
+fn table(byte: u8) -> u64 {
+    // splitmix64-style avalanche, keyed on the byte value.
+    let mut x = (byte as u64).wrapping_mul(0x9E3779B97F4A7C15);
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xBF58476D1CE4E5B9);
+    x ^= x >> 27;
+    x
+}
+
+/// Hash a single chunk with a strong digest so colliding content can
+/// be trusted to be byte-identical before reflinking onto it.
+pub fn chunk_digest(chunk: &[u8]) -> blake3::Hash {
+    blake3::hash(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_boundaries_cover_all_input() {
+        let data: Vec<u8> = (0..512 * 1024).map(|i| (i % 251) as u8).collect();
+        let lens = chunk_boundaries(&data, true);
+
+        assert_eq!(lens.iter().sum::<usize>(), data.len());
+        for len in &lens[..lens.len() - 1] {
+            assert!(*len >= MIN_CHUNK_SIZE, "chunk too small: {}", len);
+            assert!(*len <= MAX_CHUNK_SIZE, "chunk too large: {}", len);
+        }
+    }
+
+    #[test]
+    fn identical_content_chunks_identically() {
+        let data: Vec<u8> = (0..300 * 1024).map(|i| (i % 97) as u8).collect();
+        assert_eq!(chunk_boundaries(&data, true), chunk_boundaries(&data.clone(), true));
+    }
+
+    #[test]
+    fn chunk_boundaries_are_block_aligned() {
+        // Every non-final boundary must land on a DEDUP_BLOCK_SIZE
+        // multiple, or a duplicate chunk elsewhere in the tree could
+        // never actually be reflinked onto it.
+        let data: Vec<u8> = (0..512 * 1024).map(|i| (i % 233) as u8).collect();
+        let lens = chunk_boundaries(&data, true);
+
+        let mut offset = 0u64;
+        for len in &lens[..lens.len() - 1] {
+            offset += *len as u64;
+            assert_eq!(offset % DEDUP_BLOCK_SIZE, 0, "boundary at {} isn't block-aligned", offset);
+        }
+    }
+
+    #[test]
+    fn chunk_boundaries_leave_partial_tail_until_eof() {
+        // A streamed, not-yet-complete buffer shouldn't force a
+        // boundary just because the caller's read happened to stop
+        // there; the unresolved tail is left for the next call.
+        let data: Vec<u8> = (0..(MIN_CHUNK_SIZE + 1024)).map(|i| (i % 199) as u8).collect();
+        let lens = chunk_boundaries(&data, false);
+
+        assert!(lens.iter().sum::<usize>() < data.len());
+    }
+
+    #[test]
+    fn deduplicator_tracks_seen_chunks() {
+        let dedup = Deduplicator::new();
+        let digest = chunk_digest(b"some file contents");
+
+        assert!(dedup.lookup(&digest).is_none());
+
+        dedup.insert(digest, ChunkRef { path: PathBuf::from("/tmp/a"), offset: 0, len: 18 });
+        let found = dedup.lookup(&digest).expect("chunk should be indexed");
+        assert_eq!(found.path, PathBuf::from("/tmp/a"));
+        assert_eq!(found.len, 18);
+    }
+}
@@ -62,7 +62,9 @@
 //! ```
 //! [xcp]: https://crates.io/crates/xcp/
 
+pub mod archive;
 pub mod config;
+pub mod dedup;
 pub mod drivers;
 pub mod errors;
 pub mod operations;
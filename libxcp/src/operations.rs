@@ -16,6 +16,9 @@
 
 use std::{cmp, thread};
 use std::fs::{File, Metadata, read_link, create_dir_all};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::result;
 use std::str::FromStr;
@@ -26,9 +29,18 @@ use crossbeam_channel as cbc;
 use libfs::{
     allocate_file, copy_file_bytes, copy_permissions, next_sparse_segments, probably_sparse, sync, reflink, FileType,
 };
-use log::{debug, error};
+use log::{debug, error, warn};
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use nix::unistd::fchown;
 use walkdir::WalkDir;
+use xattr::FileExt as XattrFileExt;
+use xz2::stream::{LzmaOptions, Stream};
+use xz2::write::XzEncoder;
+use zstd::Encoder as ZstdEncoder;
 
+use crate::archive::ArchiveWriter;
+use crate::dedup::{chunk_boundaries, chunk_digest, ChunkRef, Deduplicator, DEDUP_BLOCK_SIZE};
 use crate::errors::{Result, XcpError};
 use crate::options::Opts;
 use crate::paths::{parse_ignore, ignore_filter};
@@ -53,11 +65,179 @@ impl FromStr for Reflink {
     }
 }
 
+/// Metadata fields that should be carried across onto the copy
+/// destination, beyond the plain byte-content and the mode bits -
+/// those are copied unconditionally by `copy_permissions()` whenever
+/// `--no-perms` isn't set, so there's no separate `mode` flag here;
+/// the fields below are applied in `CopyHandle::finalise_copy()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Preserve {
+    pub timestamps: bool,
+    pub ownership: bool,
+    pub xattr: bool,
+}
+
+impl Preserve {
+    fn any(&self) -> bool {
+        self.timestamps || self.ownership || self.xattr
+    }
+}
+
+impl FromStr for Preserve {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let mut preserve = Preserve::default();
+
+        for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            match field.to_lowercase().as_str() {
+                "all" => preserve = Preserve { timestamps: true, ownership: true, xattr: true },
+                // Mode is always preserved unless `--no-perms` is
+                // given, so this is accepted for compatibility with
+                // `cp --preserve=mode` but doesn't change anything.
+                "mode" => (),
+                "timestamps" => preserve.timestamps = true,
+                "ownership" => preserve.ownership = true,
+                "xattr" => preserve.xattr = true,
+                _ => return Err(XcpError::InvalidArguments(format!("Unexpected value for 'preserve': {}", field))),
+            }
+        }
+
+        Ok(preserve)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompressionAlgo {
+    Zstd,
+    Xz,
+}
+
+/// Destination-compression settings, e.g. from `--compress=zstd:19:27`
+/// (`algo[:level[:window_log]]`). `window_log` is the base-2 log of
+/// the match-finding window; larger values trade memory for ratio.
+/// For `Zstd` it sets the encoder's window log; for `Xz` it sets the
+/// LZMA dictionary size to `2^window_log` bytes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Compression {
+    pub algo: CompressionAlgo,
+    pub level: i32,
+    pub window_log: u32,
+}
+
+impl Compression {
+    fn suffix(&self) -> &'static str {
+        match self.algo {
+            CompressionAlgo::Zstd => "zst",
+            CompressionAlgo::Xz => "xz",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let mut fields = s.split(':');
+
+        let algo = match fields.next().unwrap_or("").to_lowercase().as_str() {
+            "zstd" => CompressionAlgo::Zstd,
+            "xz" => CompressionAlgo::Xz,
+            other => return Err(XcpError::InvalidArguments(format!("Unexpected value for 'compress': {}", other))),
+        };
+
+        let parse_field = |f: &str, name: &str| -> result::Result<Option<u32>, XcpError> {
+            if f.is_empty() {
+                return Ok(None);
+            }
+            f.parse::<u32>()
+                .map(Some)
+                .map_err(|_| XcpError::InvalidArguments(format!("Invalid {} in 'compress' value: {}", name, f)))
+        };
+
+        let level = parse_field(fields.next().unwrap_or(""), "level")?.unwrap_or(3) as i32;
+        let window_log = parse_field(fields.next().unwrap_or(""), "window-log")?.unwrap_or(27);
+
+        Ok(Compression { algo, level, window_log })
+    }
+}
+
+/// Append `.<suffix>` onto a path's file name, e.g. `out` + `zst` ->
+/// `out.zst`; used to name compressed destination files after the
+/// algorithm that produced them, since the plain name would no
+/// longer hold the bytes a reader expects.
+fn append_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Interposed between `copy_bytes` and `outfd` when `opts.compress`
+/// is set, so compressed data - rather than a zero-copy splice of the
+/// source - lands on disk. Wraps the two supported backends behind a
+/// single `Write` so `copy_compressed` doesn't need to care which one
+/// is in use.
+enum CompressWriter {
+    Zstd(ZstdEncoder<'static, File>),
+    Xz(XzEncoder<File>),
+}
+
+impl CompressWriter {
+    fn new(outfd: File, compress: &Compression) -> Result<Self> {
+        match compress.algo {
+            CompressionAlgo::Zstd => {
+                let mut encoder = ZstdEncoder::new(outfd, compress.level)?;
+                // A larger window trades encoder/decoder memory for a
+                // better ratio on inputs with distant repeats; the
+                // value replaces the window that `level` would pick.
+                encoder.window_log(compress.window_log)?;
+                Ok(CompressWriter::Zstd(encoder))
+            }
+            CompressionAlgo::Xz => {
+                let mut lzma_opts = LzmaOptions::new_preset(compress.level as u32)
+                    .map_err(|e| XcpError::CopyError(format!("Invalid xz level {}: {}", compress.level, e)))?;
+                lzma_opts.dict_size(1u32 << compress.window_log);
+                let stream = Stream::new_lzma_encoder(&lzma_opts)
+                    .map_err(|e| XcpError::CopyError(format!("Failed to create xz encoder: {}", e)))?;
+                Ok(CompressWriter::Xz(XzEncoder::new_stream(outfd, stream)))
+            }
+        }
+    }
+
+    /// Flush any buffered output and the format trailer, and hand
+    /// back the underlying file so its on-disk size can be reported.
+    fn finish(self) -> Result<File> {
+        let outfd = match self {
+            CompressWriter::Zstd(e) => e.finish()?,
+            CompressWriter::Xz(e) => e.finish()?,
+        };
+        Ok(outfd)
+    }
+}
+
+impl Write for CompressWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            CompressWriter::Zstd(e) => e.write(buf),
+            CompressWriter::Xz(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            CompressWriter::Zstd(e) => e.flush(),
+            CompressWriter::Xz(e) => e.flush(),
+        }
+    }
+}
+
 
 #[derive(Debug)]
 pub struct CopyHandle {
     pub infd: File,
     pub outfd: File,
+    pub to: PathBuf,
     pub metadata: Metadata,
     pub opts: Arc<Opts>,
 }
@@ -67,12 +247,23 @@ impl CopyHandle {
         let infd = File::open(from)?;
         let metadata = infd.metadata()?;
 
-        let outfd = File::create(to)?;
-        allocate_file(&outfd, metadata.len())?;
+        // Compressed output lands under a suffixed name (out.zst) and
+        // its size isn't known up-front, so skip the preallocation
+        // that the plain-copy path uses to reduce fragmentation.
+        let to = match &opts.compress {
+            Some(compress) => append_suffix(to, compress.suffix()),
+            None => to.to_path_buf(),
+        };
+
+        let outfd = File::create(&to)?;
+        if opts.compress.is_none() {
+            allocate_file(&outfd, metadata.len())?;
+        }
 
         let handle = CopyHandle {
             infd,
             outfd,
+            to,
             metadata,
             opts: opts.clone(),
         };
@@ -131,28 +322,338 @@ impl CopyHandle {
     }
 
     pub fn copy_file(&self, updates: &StatSender) -> Result<u64> {
-        if self.try_reflink()? {
-            return Ok(self.metadata.len());
-        }
-        let total = if probably_sparse(&self.infd)? {
+        // Compression has to see every byte, so it's incompatible
+        // with both the zero-copy reflink path and dedup's reflinked
+        // chunks, which only work on byte-identical ranges.
+        let total = if let Some(compress) = &self.opts.compress {
+            self.copy_compressed(compress, updates)?
+        } else if self.try_reflink()? {
+            self.metadata.len()
+        } else if self.opts.dedup {
+            self.copy_deduped(updates)?
+        } else if probably_sparse(&self.infd)? {
             self.copy_sparse(updates)?
         } else {
             self.copy_bytes(self.metadata.len(), updates)?
         };
 
+        // Runs on this same worker thread, right after the bytes it's
+        // checking were written, rather than being deferred to a
+        // separate pass over the whole tree.
+        if self.opts.verify {
+            self.verify_copy(updates)?;
+        }
+
+        Ok(total)
+    }
+
+    /// Re-read source and destination and confirm they're
+    /// byte-identical, rather than trusting the copy path blindly -
+    /// worth doing after a reflink/dedup copy, or when the underlying
+    /// hardware is suspect. Doesn't apply under `opts.compress`: the
+    /// destination holds a compressed representation there, not a
+    /// byte-identical copy, so there's nothing to compare.
+    fn verify_copy(&self, updates: &StatSender) -> Result<()> {
+        if self.opts.compress.is_some() {
+            return Ok(());
+        }
+
+        let mut src = self.infd.try_clone()?;
+        let mut dst = File::open(&self.to)?;
+        src.seek(SeekFrom::Start(0))?;
+
+        if probably_sparse(&src)? {
+            self.verify_sparse(&mut src, &mut dst, updates)
+        } else {
+            self.verify_segment(&mut src, &mut dst, self.metadata.len(), updates)
+        }
+    }
+
+    /// Sparse counterpart to `verify_copy`'s whole-file path: walks
+    /// the same hole map `copy_sparse` does and only hashes the data
+    /// regions, treating holes on both sides as implicitly equal
+    /// zero-fill rather than reading and comparing them.
+    fn verify_sparse(&self, src: &mut File, dst: &mut File, updates: &StatSender) -> Result<()> {
+        let len = self.metadata.len();
+        let mut pos = 0;
+
+        while pos < len {
+            let (next_data, next_hole) = next_sparse_segments(src, dst, pos)?;
+            self.verify_segment(src, dst, next_hole - next_data, updates)?;
+            pos = next_hole;
+        }
+
+        Ok(())
+    }
+
+    /// Hash-compare the next `len` bytes from the current position of
+    /// `src` and `dst` in `Opts::batch_size()` chunks, streaming
+    /// blake3 over each chunk rather than buffering either file
+    /// whole, so a mismatch aborts as soon as it's found.
+    fn verify_segment(&self, src: &mut File, dst: &mut File, len: u64, updates: &StatSender) -> Result<()> {
+        let batch = self.opts.batch_size();
+        let mut remaining = len;
+        let mut src_buf = vec![0u8; batch as usize];
+        let mut dst_buf = vec![0u8; batch as usize];
+
+        while remaining > 0 {
+            let chunk_len = cmp::min(remaining, batch) as usize;
+            let offset = src.stream_position()?;
+
+            src.read_exact(&mut src_buf[..chunk_len])?;
+            dst.read_exact(&mut dst_buf[..chunk_len])?;
+
+            if blake3::hash(&src_buf[..chunk_len]) != blake3::hash(&dst_buf[..chunk_len]) {
+                updates.send(StatusUpdate::Error(
+                    XcpError::VerificationFailed(self.to.clone(), offset)))?;
+                return Err(XcpError::VerificationFailed(self.to.clone(), offset).into());
+            }
+
+            updates.send(StatusUpdate::Verified(chunk_len as u64))?;
+            remaining -= chunk_len as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Copy via a [`CompressWriter`] interposed in front of `outfd`.
+    /// Unlike `copy_sparse`, this doesn't skip holes: `next_sparse_segments`
+    /// seeks `outfd` to jump over them, but `writer` holds a *clone* of
+    /// that same fd - sharing its file-position cursor - so a seek
+    /// meant to skip a hole in the destination instead yanks the
+    /// cursor out from under the compressor mid-stream, scattering its
+    /// output across the file instead of appending it. A compressed
+    /// stream has no concept of a hole anyway, so source holes are
+    /// just read (as the zero-filled bytes the kernel hands back for
+    /// them) and compressed like any other data; the encoder handles
+    /// long zero runs cheaply on its own.
+    fn copy_compressed(&self, compress: &Compression, updates: &StatSender) -> Result<u64> {
+        let outfd = self.outfd.try_clone()?;
+        let mut writer = CompressWriter::new(outfd, compress)?;
+
+        let total = self.copy_compressed_bytes(&mut writer, self.metadata.len(), updates)?;
+
+        let compressed_size = writer.finish()?.metadata()?.len();
+        updates.send(StatusUpdate::Compressed(compressed_size))?;
+
         Ok(total)
     }
 
+    /// Compressed counterpart to `copy_bytes`: reads `len` bytes from
+    /// the source and writes them through `writer` instead of calling
+    /// `copy_file_bytes`, since a compressing writer isn't a file
+    /// descriptor `copy_file_bytes` can splice into. Progress is still
+    /// reported in terms of uncompressed bytes, matching every other
+    /// copy path.
+    fn copy_compressed_bytes(&self, writer: &mut CompressWriter, len: u64, updates: &StatSender) -> Result<u64> {
+        let mut remaining = len;
+        let mut buf = vec![0u8; self.opts.batch_size() as usize];
+
+        while remaining > 0 {
+            let to_read = cmp::min(remaining, buf.len() as u64) as usize;
+            (&self.infd).read_exact(&mut buf[..to_read])?;
+            writer.write_all(&buf[..to_read])?;
+            updates.send(StatusUpdate::Copied(to_read as u64))?;
+            remaining -= to_read as u64;
+        }
+
+        Ok(len)
+    }
+
+    /// Copy via content-defined chunking, reflinking any chunk whose
+    /// digest already appears in `opts.dedup_index` - scoped to the
+    /// run this `Opts` covers, rather than a process-wide static, so
+    /// an embedder making several `copy_all()` calls in one process
+    /// (see `lib.rs`) gets an index per run instead of one that leaks
+    /// and cross-contaminates across logically separate copies -
+    /// instead of writing its bytes again. Falls through to a plain
+    /// write for chunks we haven't seen, for FS-unaligned chunks, and
+    /// whenever the filesystem rejects the reflink (e.g. `EOPNOTSUPP`
+    /// on a FS that doesn't support it, or crossing a `EXDEV` mountpoint).
+    ///
+    /// Reads the source through `Opts::batch_size()` reads like every
+    /// other copy path rather than materialising it whole: `pending`
+    /// only ever holds one read's worth plus a chunk not yet resolved
+    /// by [`chunk_boundaries`], bounded by `MAX_CHUNK_SIZE`, not by the
+    /// size of the file being copied.
+    fn copy_deduped(&self, updates: &StatSender) -> Result<u64> {
+        let index = &self.opts.dedup_index;
+
+        let mut buf = vec![0u8; self.opts.batch_size() as usize];
+        let mut pending: Vec<u8> = Vec::new();
+        let mut offset = 0u64;
+
+        loop {
+            let n = (&self.infd).read(&mut buf)?;
+            let eof = n == 0;
+            pending.extend_from_slice(&buf[..n]);
+
+            let mut consumed = 0usize;
+            for len in chunk_boundaries(&pending, eof) {
+                let chunk = &pending[consumed..consumed + len];
+                self.copy_dedup_chunk(index, offset, chunk, updates)?;
+                offset += len as u64;
+                consumed += len;
+            }
+            pending.drain(..consumed);
+
+            if eof {
+                break;
+            }
+        }
+
+        Ok(offset)
+    }
+
+    /// Hash, look up and either reflink or write out a single chunk
+    /// produced by `copy_deduped`'s streaming chunker, recording it in
+    /// `index` if it had to be written so a later duplicate can
+    /// reflink it instead.
+    fn copy_dedup_chunk(&self, index: &Deduplicator, offset: u64, chunk: &[u8], updates: &StatSender) -> Result<()> {
+        let digest = chunk_digest(chunk);
+        let len = chunk.len() as u64;
+
+        // FICLONERANGE requires both ends of the range, and its
+        // length, to be filesystem-block-aligned; a misaligned
+        // candidate would just earn an EINVAL from the kernel, so
+        // filter those out here rather than relying on that.
+        let aligned = offset % DEDUP_BLOCK_SIZE == 0 && len % DEDUP_BLOCK_SIZE == 0;
+
+        let deduped = if aligned {
+            match index.lookup(&digest) {
+                Some(existing) if existing.offset % DEDUP_BLOCK_SIZE == 0
+                    && existing.len % DEDUP_BLOCK_SIZE == 0
+                    && existing.len == len =>
+                {
+                    self.try_reflink_chunk(&existing, offset, len)?
+                }
+                _ => false,
+            }
+        } else {
+            false
+        };
+
+        if deduped {
+            updates.send(StatusUpdate::Deduplicated(len))?;
+        } else {
+            // A preceding chunk may have been satisfied by a reflink,
+            // which moves bytes via FICLONERANGE at an explicit offset
+            // without touching outfd's cursor - so this write can't
+            // assume the cursor is already at `offset`.
+            (&self.outfd).seek(SeekFrom::Start(offset))?;
+            (&self.outfd).write_all(chunk)?;
+            updates.send(StatusUpdate::Copied(len))?;
+            index.insert(digest, ChunkRef { path: self.to.clone(), offset, len });
+        }
+
+        Ok(())
+    }
+
+    /// Attempt to reflink `len` bytes from `existing` onto `self.outfd`
+    /// at `dst_offset`; returns `false` (rather than erroring) for any
+    /// condition that just means "this chunk isn't reflinkable",
+    /// leaving the caller to fall back to a normal write.
+    fn try_reflink_chunk(&self, existing: &ChunkRef, dst_offset: u64, len: u64) -> Result<bool> {
+        let src = match File::open(&existing.path) {
+            Ok(f) => f,
+            Err(e) => {
+                debug!("Dedup source {:?} unavailable, falling back to copy: {}", existing.path, e);
+                return Ok(false);
+            }
+        };
+
+        reflink_range(&src, existing.offset, &self.outfd, dst_offset, len)
+    }
+
     fn finalise_copy(&self) -> Result<()> {
+        let preserve = self.opts.preserve;
+        if preserve.any() {
+            if preserve.timestamps {
+                self.preserve_timestamps()?;
+            }
+            if preserve.ownership {
+                // Before mode: chown clears setuid/setgid on Linux
+                // unless the process holds CAP_FSETID, so applying it
+                // after copy_permissions would silently drop bits that
+                // were just correctly set.
+                self.preserve_ownership()?;
+            }
+            if preserve.xattr {
+                self.preserve_xattrs()?;
+            }
+        }
+
         if !self.opts.no_perms {
             copy_permissions(&self.infd, &self.outfd)?;
         }
+
         if self.opts.fsync {
             debug!("Syncing file {:?}", self.outfd);
             sync(&self.outfd)?;
         }
         Ok(())
     }
+
+    /// Apply the source's nanosecond-resolution atime/mtime to the
+    /// destination; a plain `SystemTime` round-trip would truncate to
+    /// whole seconds and defeat build-system mtime comparisons.
+    fn preserve_timestamps(&self) -> Result<()> {
+        let atime = TimeSpec::new(self.metadata.atime(), self.metadata.atime_nsec());
+        let mtime = TimeSpec::new(self.metadata.mtime(), self.metadata.mtime_nsec());
+
+        utimensat(None, &self.to, &atime, &mtime, UtimensatFlags::FollowSymlink)
+            .map_err(|e| XcpError::CopyError(format!("Failed to set timestamps on {:?}: {}", self.to, e)))?;
+
+        Ok(())
+    }
+
+    /// Re-apply the source uid/gid to the destination, degrading
+    /// silently when we don't have the privileges to chown (e.g. when
+    /// copying as a non-root user), matching the behaviour of `cp -p`.
+    fn preserve_ownership(&self) -> Result<()> {
+        let uid = self.metadata.uid();
+        let gid = self.metadata.gid();
+
+        if let Err(e) = fchown(&self.outfd, Some(uid.into()), Some(gid.into())) {
+            warn!("Failed to preserve ownership of {:?}: {}", self.to, e);
+        }
+
+        Ok(())
+    }
+
+    /// Copy extended attributes from source to destination.
+    fn preserve_xattrs(&self) -> Result<()> {
+        let names = match self.infd.list_xattr() {
+            Ok(names) => names,
+            Err(e) => {
+                warn!("Failed to list xattrs on {:?}: {}", self.to, e);
+                return Ok(());
+            }
+        };
+
+        for name in names {
+            // Degrade silently per-xattr, matching `preserve_ownership`:
+            // one unsupported/oversized attribute (e.g. `EOPNOTSUPP` on
+            // a FS without xattr support, or `E2BIG`) shouldn't abort
+            // the rest, and propagating via `?` here would also skip
+            // the fsync below since the caller chains straight through.
+            let value = match self.infd.get_xattr(&name) {
+                Ok(value) => value,
+                Err(e) => {
+                    warn!("Failed to read xattr {:?} on {:?}: {}", name, self.to, e);
+                    continue;
+                }
+            };
+
+            if let Some(value) = value {
+                if let Err(e) = self.outfd.set_xattr(&name, &value) {
+                    warn!("Failed to set xattr {:?} on {:?}: {}", name, self.to, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Drop for CopyHandle {
@@ -167,6 +668,17 @@ impl Drop for CopyHandle {
 #[derive(Debug)]
 pub enum StatusUpdate {
     Copied(u64),
+    /// Bytes satisfied by a dedup reflink rather than written, so
+    /// progress reporting can reflect the I/O actually saved.
+    Deduplicated(u64),
+    /// Final on-disk size of a compressed destination file, reported
+    /// once the copy finishes; `Copied` updates during the copy still
+    /// count uncompressed source bytes, so this is the only signal of
+    /// how much the compression actually saved.
+    Compressed(u64),
+    /// Bytes confirmed identical by `--verify`'s post-copy hash
+    /// comparison, reported as verification proceeds.
+    Verified(u64),
     Size(u64),
     Error(XcpError)
 }
@@ -207,7 +719,16 @@ impl StatSender {
 #[derive(Debug)]
 pub enum Operation {
     Copy(PathBuf, PathBuf),
-    Link(PathBuf, PathBuf),
+    /// (source symlink path, link target, destination path). The
+    /// source path is kept around, in addition to the already
+    /// resolved link target, so archive mode can re-stat it for a tar
+    /// header without the walker needing to thread metadata through
+    /// the channel.
+    Link(PathBuf, PathBuf, PathBuf),
+    /// (source directory path, destination path); only sent in
+    /// archive mode; the disk-tree path creates directories directly
+    /// as it walks, per the comment in `tree_walker`.
+    Dir(PathBuf, PathBuf),
     Special(PathBuf, PathBuf),
 }
 
@@ -265,17 +786,22 @@ pub fn tree_walker(
                 }
 
                 FileType::Symlink => {
-                    let lfile = read_link(from)?;
+                    let lfile = read_link(&from)?;
                     debug!("Send symlink operation {:?} to {:?}", lfile, target);
-                    work_tx.send(Operation::Link(lfile, target))?;
+                    work_tx.send(Operation::Link(from, lfile, target))?;
                 }
 
                 FileType::Dir => {
-                    // Create dir tree immediately as we can't
-                    // guarantee a worker will action the creation
-                    // before a subsequent copy operation requires it.
-                    debug!("Creating target directory {:?}", target);
-                    create_dir_all(&target)?;
+                    if opts.archive {
+                        debug!("Send dir operation {:?} to {:?}", from, target);
+                        work_tx.send(Operation::Dir(from, target))?;
+                    } else {
+                        // Create dir tree immediately as we can't
+                        // guarantee a worker will action the creation
+                        // before a subsequent copy operation requires it.
+                        debug!("Creating target directory {:?}", target);
+                        create_dir_all(&target)?;
+                    }
                 }
 
                 FileType::Socket | FileType::Char | FileType::Fifo => {
@@ -295,6 +821,497 @@ pub fn tree_walker(
     Ok(())
 }
 
+/// Sequential consumer for archive-destination mode: drains `work_rx`
+/// in walk order (tar has no concept of out-of-order writes) and
+/// appends each operation as an entry in a single `.tar` at `dest`,
+/// instead of the per-file `File::create` the disk-tree drivers use.
+pub fn archive_worker(
+    work_rx: cbc::Receiver<Operation>,
+    dest: &Path,
+    stats: StatSender,
+) -> Result<()> {
+    debug!("Starting archive worker {:?}", thread::current().id());
+    let mut archive = ArchiveWriter::create(dest)?;
+
+    // Entries are written using the destination path relative to
+    // `dest`, mirroring how the disk-tree path joins onto target_base.
+    let archive_path = |target: &Path| -> PathBuf {
+        target.strip_prefix(dest).map(Path::to_path_buf).unwrap_or_else(|_| target.to_path_buf())
+    };
+
+    for op in work_rx {
+        match op {
+            Operation::Copy(from, target) => {
+                let meta = from.symlink_metadata()?;
+                debug!("Archiving file {:?} as {:?}", from, target);
+                archive.append_file(&archive_path(&target), &from, &meta)?;
+                stats.send(StatusUpdate::Copied(meta.len()))?;
+            }
+
+            Operation::Link(from, lfile, target) => {
+                let meta = from.symlink_metadata()?;
+                debug!("Archiving symlink {:?} -> {:?} as {:?}", from, lfile, target);
+                archive.append_symlink(&archive_path(&target), &lfile, &meta)?;
+            }
+
+            Operation::Dir(from, target) => {
+                // The walk root itself comes through as a `Dir` op
+                // too; before any other entry exists, `target ==
+                // dest`, so `archive_path` strips it down to an empty
+                // path. An archive entry with no name isn't useful,
+                // so skip it - the walk's child entries carry the
+                // tree structure on their own.
+                let path = archive_path(&target);
+                if empty_path(&path) {
+                    debug!("Skipping archive entry for walk root {:?}", from);
+                    continue;
+                }
+
+                let meta = from.symlink_metadata()?;
+                debug!("Archiving directory {:?} as {:?}", from, target);
+                archive.append_dir(&path, &meta)?;
+            }
+
+            Operation::Special(from, _target) => {
+                warn!("Skipping special file {:?}: unsupported in archive mode", from);
+            }
+        }
+    }
+
+    archive.finish()?;
+    debug!("Archive worker finished: {:?}", thread::current().id());
+
+    Ok(())
+}
+
 fn empty_path(path: &Path) -> bool {
     *path == PathBuf::new()
-}
\ No newline at end of file
+}
+
+/// `ioctl(2)` value for `FICLONERANGE`, i.e. `_IOW(0x94, 13, struct
+/// file_clone_range)`; not exposed by `libc` directly.
+const FICLONERANGE: u64 = 0x4020_940d;
+
+#[repr(C)]
+struct FileCloneRange {
+    src_fd: i64,
+    src_offset: u64,
+    src_length: u64,
+    dest_offset: u64,
+}
+
+/// Clone `len` bytes from `(src, src_offset)` onto `(dst, dst_offset)`
+/// via `FICLONERANGE`, sharing the underlying storage instead of
+/// copying it. Returns `Ok(false)` for any condition that just means
+/// "can't reflink here" (unsupported FS, cross-device, misaligned
+/// range) so the caller can fall back to a normal copy.
+fn reflink_range(src: &File, src_offset: u64, dst: &File, dst_offset: u64, len: u64) -> Result<bool> {
+    let range = FileCloneRange {
+        src_fd: src.as_raw_fd() as i64,
+        src_offset,
+        src_length: len,
+        dest_offset: dst_offset,
+    };
+
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), FICLONERANGE as _, &range) };
+    if ret == 0 {
+        return Ok(true);
+    }
+
+    match std::io::Error::last_os_error().raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::EXDEV) | Some(libc::EINVAL) => Ok(false),
+        _ => Err(XcpError::CopyError(format!(
+            "reflink_range {:?}@{}->{:?}@{} failed: {}", src, src_offset, dst, dst_offset,
+            std::io::Error::last_os_error())).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    fn stat_sender() -> (StatSender, cbc::Receiver<StatusUpdate>) {
+        let (tx, rx) = cbc::unbounded();
+        (StatSender::new(tx, &Arc::new(Opts::default())), rx)
+    }
+
+    #[test]
+    fn preserve_timestamps_matches_source() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("source");
+        let to = dir.path().join("dest");
+        std::fs::write(&from, b"content")?;
+
+        // Give the source a timestamp that's clearly distinguishable
+        // from "now", so a no-op finalise_copy can't pass by accident.
+        let past = filetime::FileTime::from_unix_time(1_000_000, 123_456_789);
+        filetime::set_file_times(&from, past, past)?;
+
+        let opts = Arc::new(Opts {
+            preserve: Preserve { timestamps: true, ..Default::default() },
+            ..Default::default()
+        });
+        let (stats, _rx) = stat_sender();
+        let handle = CopyHandle::new(&from, &to, &opts)?;
+        handle.copy_file(&stats)?;
+        handle.finalise_copy()?;
+
+        let src_meta = from.metadata()?;
+        let dst_meta = to.metadata()?;
+        assert_eq!(src_meta.mtime(), dst_meta.mtime());
+        assert_eq!(src_meta.mtime_nsec(), dst_meta.mtime_nsec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn preserve_xattrs_round_trips() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("source");
+        let to = dir.path().join("dest");
+        std::fs::write(&from, b"content")?;
+
+        if xattr::set(&from, "user.xcp_test", b"hello").is_err() {
+            // Filesystem doesn't support xattrs (e.g. tmpfs without
+            // user_xattr); nothing to test here.
+            return Ok(());
+        }
+
+        let opts = Arc::new(Opts {
+            preserve: Preserve { xattr: true, ..Default::default() },
+            ..Default::default()
+        });
+        let (stats, _rx) = stat_sender();
+        let handle = CopyHandle::new(&from, &to, &opts)?;
+        handle.copy_file(&stats)?;
+        handle.finalise_copy()?;
+
+        let value = xattr::get(&to, "user.xcp_test")?;
+        assert_eq!(value, Some(b"hello".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn preserve_flags_parse_from_str() -> Result<()> {
+        let all: Preserve = "all".parse()?;
+        assert_eq!(all, Preserve { timestamps: true, ownership: true, xattr: true });
+
+        let some: Preserve = "timestamps,xattr".parse()?;
+        assert_eq!(some, Preserve { timestamps: true, xattr: true, ..Default::default() });
+
+        // Accepted for `cp`-compatibility, but a no-op: mode is
+        // always preserved unless `--no-perms` is given.
+        assert_eq!("mode".parse::<Preserve>()?, Preserve::default());
+
+        assert!("bogus".parse::<Preserve>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn compress_settings_parse_from_str() -> Result<()> {
+        let defaults: Compression = "zstd".parse()?;
+        assert_eq!(defaults, Compression { algo: CompressionAlgo::Zstd, level: 3, window_log: 27 });
+
+        let tuned: Compression = "zstd:19:30".parse()?;
+        assert_eq!(tuned, Compression { algo: CompressionAlgo::Zstd, level: 19, window_log: 30 });
+
+        let xz: Compression = "xz:6".parse()?;
+        assert_eq!(xz, Compression { algo: CompressionAlgo::Xz, level: 6, window_log: 27 });
+
+        assert!("bogus".parse::<Compression>().is_err());
+        assert!("zstd:notanumber".parse::<Compression>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_copy_round_trips_content() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("source");
+        let to = dir.path().join("dest");
+        let content: Vec<u8> = (0..64 * 1024).map(|i| (i % 17) as u8).collect();
+        std::fs::write(&from, &content)?;
+
+        let opts = Arc::new(Opts {
+            compress: Some(Compression { algo: CompressionAlgo::Zstd, level: 3, window_log: 27 }),
+            ..Default::default()
+        });
+        let (stats, rx) = stat_sender();
+        let handle = CopyHandle::new(&from, &to, &opts)?;
+        handle.copy_file(&stats)?;
+        drop(stats);
+
+        let dest_path = dir.path().join("dest.zst");
+        assert!(dest_path.exists(), "compressed output should land under a suffixed name");
+        assert_eq!(zstd::decode_all(File::open(&dest_path)?)?, content);
+
+        let compressed_size: u64 = rx.iter()
+            .filter_map(|s| if let StatusUpdate::Compressed(n) = s { Some(n) } else { None })
+            .sum();
+        assert!(compressed_size > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_copy_handles_sparse_source() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("source");
+        let to = dir.path().join("dest");
+
+        // An explicit hole (never written, just `set_len`'d past),
+        // followed by a data region - the shape that previously
+        // confused `CompressWriter`'s shared-cursor clone of `outfd`.
+        let tail = b"trailing data after a hole";
+        let hole_len = 256 * 1024u64;
+        {
+            let mut f = std::fs::File::create(&from)?;
+            f.set_len(hole_len)?;
+            f.seek(SeekFrom::Start(hole_len))?;
+            f.write_all(tail)?;
+        }
+        let mut expected = vec![0u8; hole_len as usize];
+        expected.extend_from_slice(tail);
+
+        let opts = Arc::new(Opts {
+            compress: Some(Compression { algo: CompressionAlgo::Zstd, level: 3, window_log: 27 }),
+            ..Default::default()
+        });
+        let (stats, _rx) = stat_sender();
+        let handle = CopyHandle::new(&from, &to, &opts)?;
+        handle.copy_file(&stats)?;
+
+        let dest_path = dir.path().join("dest.zst");
+        assert_eq!(zstd::decode_all(File::open(&dest_path)?)?, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_passes_on_matching_copy() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("source");
+        let to = dir.path().join("dest");
+        std::fs::write(&from, b"content that should match")?;
+
+        let opts = Arc::new(Opts { verify: true, ..Default::default() });
+        let (stats, rx) = stat_sender();
+        let handle = CopyHandle::new(&from, &to, &opts)?;
+        handle.copy_file(&stats)?;
+        drop(stats);
+
+        let verified: u64 = rx.iter()
+            .filter_map(|s| if let StatusUpdate::Verified(n) = s { Some(n) } else { None })
+            .sum();
+        assert_eq!(verified, "content that should match".len() as u64);
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_detects_corrupted_destination() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("source");
+        let to = dir.path().join("dest");
+        std::fs::write(&from, b"content that should match")?;
+
+        let opts = Arc::new(Opts::default());
+        let (stats, _rx) = stat_sender();
+        let handle = CopyHandle::new(&from, &to, &opts)?;
+        handle.copy_file(&stats)?;
+
+        // Corrupt the destination after the copy, so `verify_copy` is
+        // exercised directly against a deliberately-broken file.
+        std::fs::write(&to, b"content that does NOT match")?;
+
+        let (stats, rx) = stat_sender();
+        let result = handle.verify_copy(&stats);
+        drop(stats);
+
+        assert!(result.is_err());
+        assert!(rx.iter().any(|s| matches!(s, StatusUpdate::Error(XcpError::VerificationFailed(..)))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn dedup_copy_preserves_content() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("source");
+        let to = dir.path().join("dest");
+        // Large enough to span several average-sized chunks.
+        let content: Vec<u8> = (0..200 * 1024).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&from, &content)?;
+
+        let opts = Arc::new(Opts { dedup: true, ..Default::default() });
+        let (stats, _rx) = stat_sender();
+        let handle = CopyHandle::new(&from, &to, &opts)?;
+        handle.copy_file(&stats)?;
+
+        assert_eq!(std::fs::read(&to)?, content);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dedup_copy_reuses_chunks_from_earlier_file() -> Result<()> {
+        let dir = TempDir::new()?;
+        let content: Vec<u8> = (0..200 * 1024).map(|i| (i % 181) as u8).collect();
+
+        let from_a = dir.path().join("a");
+        let to_a = dir.path().join("a.out");
+        std::fs::write(&from_a, &content)?;
+
+        let from_b = dir.path().join("b");
+        let to_b = dir.path().join("b.out");
+        std::fs::write(&from_b, &content)?;
+
+        let opts = Arc::new(Opts { dedup: true, ..Default::default() });
+        let (stats, rx) = stat_sender();
+
+        CopyHandle::new(&from_a, &to_a, &opts)?.copy_file(&stats)?;
+        CopyHandle::new(&from_b, &to_b, &opts)?.copy_file(&stats)?;
+        drop(stats);
+
+        // Whether or not the test filesystem actually supports
+        // FICLONERANGE, the copied content must always be correct.
+        assert_eq!(std::fs::read(&to_b)?, content);
+
+        // Every chunk of `b` is found in the index at `a.out`, the
+        // earlier file's copy, rather than at `b.out`.
+        let mut start = 0;
+        for len in chunk_boundaries(&content, true) {
+            let found = opts.dedup_index.lookup(&chunk_digest(&content[start..start + len]))
+                .expect("chunk should be indexed");
+            assert_eq!(found.path, to_a);
+            start += len;
+        }
+
+        let saved: u64 = rx.iter()
+            .filter_map(|s| if let StatusUpdate::Deduplicated(n) = s { Some(n) } else { None })
+            .sum();
+        debug!("Dedup test saved {} bytes via reflink", saved);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dedup_copy_handles_partial_overlap() -> Result<()> {
+        let dir = TempDir::new()?;
+        let shared: Vec<u8> = (0..200 * 1024).map(|i| (i % 181) as u8).collect();
+
+        let from_a = dir.path().join("a");
+        let to_a = dir.path().join("a.out");
+        std::fs::write(&from_a, &shared)?;
+
+        // `b` repeats the same leading bytes as `a` (so the dedup
+        // index has a hit partway through the file), then diverges -
+        // a later, non-deduped write in the same file must land at
+        // its own offset rather than clobbering the earlier chunk.
+        let mut content_b = shared[..100 * 1024].to_vec();
+        content_b.extend((0..100 * 1024).map(|i| (i % 223) as u8));
+
+        let from_b = dir.path().join("b");
+        let to_b = dir.path().join("b.out");
+        std::fs::write(&from_b, &content_b)?;
+
+        let opts = Arc::new(Opts { dedup: true, ..Default::default() });
+        let (stats, _rx) = stat_sender();
+
+        CopyHandle::new(&from_a, &to_a, &opts)?.copy_file(&stats)?;
+        CopyHandle::new(&from_b, &to_b, &opts)?.copy_file(&stats)?;
+
+        assert_eq!(std::fs::read(&to_b)?, content_b);
+
+        Ok(())
+    }
+
+    #[test]
+    fn archive_mode_round_trips_tree() -> Result<()> {
+        let root = TempDir::new()?;
+        let src = root.path().join("src");
+        std::fs::create_dir_all(src.join("subdir"))?;
+        std::fs::write(src.join("a.txt"), b"file a")?;
+        std::fs::write(src.join("subdir/b.txt"), b"file b")?;
+        std::os::unix::fs::symlink("a.txt", src.join("link_to_a"))?;
+
+        let archive_path = root.path().join("out.tar");
+        let opts = Opts { archive: true, ..Default::default() };
+
+        let (work_tx, work_rx) = cbc::unbounded();
+        let (stats, _rx) = stat_sender();
+        tree_walker(vec![src.clone()], &archive_path, &opts, work_tx, stats.clone())?;
+        archive_worker(work_rx, &archive_path, stats)?;
+
+        let extract_dir = TempDir::new()?;
+        let mut tar = tar::Archive::new(File::open(&archive_path)?);
+        tar.unpack(extract_dir.path())?;
+
+        assert_eq!(std::fs::read(extract_dir.path().join("a.txt"))?, b"file a");
+        assert_eq!(std::fs::read(extract_dir.path().join("subdir/b.txt"))?, b"file b");
+        assert_eq!(std::fs::read_link(extract_dir.path().join("link_to_a"))?, PathBuf::from("a.txt"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn archive_mode_skips_root_entry() -> Result<()> {
+        let root = TempDir::new()?;
+        let src = root.path().join("src");
+        std::fs::create_dir_all(&src)?;
+        std::fs::write(src.join("a.txt"), b"file a")?;
+
+        let archive_path = root.path().join("out.tar");
+        let opts = Opts { archive: true, ..Default::default() };
+
+        let (work_tx, work_rx) = cbc::unbounded();
+        let (stats, _rx) = stat_sender();
+        tree_walker(vec![src.clone()], &archive_path, &opts, work_tx, stats.clone())?;
+        archive_worker(work_rx, &archive_path, stats)?;
+
+        let mut tar = tar::Archive::new(File::open(&archive_path)?);
+        for entry in tar.entries()? {
+            let entry = entry?;
+            assert!(!entry.path()?.as_os_str().is_empty(),
+                "archive should not contain an entry for the walk root");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn archive_mode_preserves_symlink_mtime() -> Result<()> {
+        let root = TempDir::new()?;
+        let src = root.path().join("src");
+        std::fs::create_dir_all(&src)?;
+        std::fs::write(src.join("a.txt"), b"file a")?;
+        std::os::unix::fs::symlink("a.txt", src.join("link_to_a"))?;
+
+        let past = filetime::FileTime::from_unix_time(1_000_000, 0);
+        filetime::set_symlink_file_times(src.join("link_to_a"), past, past)?;
+
+        let archive_path = root.path().join("out.tar");
+        let opts = Opts { archive: true, ..Default::default() };
+
+        let (work_tx, work_rx) = cbc::unbounded();
+        let (stats, _rx) = stat_sender();
+        tree_walker(vec![src.clone()], &archive_path, &opts, work_tx, stats.clone())?;
+        archive_worker(work_rx, &archive_path, stats)?;
+
+        let mut tar = tar::Archive::new(File::open(&archive_path)?);
+        let mtime = tar.entries()?
+            .find_map(|e| {
+                let e = e.ok()?;
+                if e.path().ok()?.ends_with("link_to_a") { e.header().mtime().ok() } else { None }
+            })
+            .expect("symlink entry should be present");
+
+        assert_eq!(mtime, 1_000_000);
+
+        Ok(())
+    }
+}